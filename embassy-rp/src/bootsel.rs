@@ -7,10 +7,18 @@
 //!
 //! This module provides functionality to poll BOOTSEL from an embassy application.
 
+use embassy_time::{Duration, Instant, Timer};
 use rp_pac;
 use rp_pac::io::vals::Oeover;
 
 use crate::multicore;
+use crate::rom_data;
+
+/// Default time given for the floating CS line to settle before it's sampled, in
+/// microseconds. This matches what's worked reliably in practice; boards with
+/// different flash pull-up RC characteristics can override it via
+/// [`poll_bootsel_with_settle`].
+pub const DEFAULT_CS_SETTLE_US: u32 = 8;
 
 /// Polls the BOOTSEL button. Returns true if the button is pressed.
 ///
@@ -31,10 +39,24 @@ use crate::multicore;
 ///  * Bypassing XIP and accessing SSI directly
 ///
 pub unsafe fn poll_bootsel_unsafe() -> bool {
+    poll_bootsel_with_settle(DEFAULT_CS_SETTLE_US)
+}
+
+/// Like [`poll_bootsel_unsafe`], but lets the caller pick how long the floating CS
+/// line is given to settle before it's sampled, trading latency for reliability.
+///
+/// # Safety
+///
+/// Same requirements as [`poll_bootsel_unsafe`].
+pub unsafe fn poll_bootsel_with_settle(settle_us: u32) -> bool {
     assert!(rp_pac::SIO.cpuid().read() == 0, "Need to be on core 0");
 
     multicore::pause_core1();
 
+    // The settle loop below is a cycle-counted delay, not a timer, so it needs to be
+    // converted from microseconds using the clock it'll actually run at.
+    let settle_cycles = settle_us.saturating_mul(crate::clocks::clk_sys_freq() / 1_000_000);
+
     let button_state = critical_section::with(|cs| {
         // Wait for all DMA channels accessing flash to finish
         const SRAM_LOWER: u32 = 0x2000_0000;
@@ -45,7 +67,7 @@ pub unsafe fn poll_bootsel_unsafe() -> bool {
         // Wait for completion of any streaming reads
         while rp_pac::XIP_CTRL.stream_ctr().read().0 > 0 {}
 
-        unsafe { poll_bootsel_ramfunc(&cs) }
+        unsafe { poll_bootsel_ramfunc(&cs, settle_cycles) }
     });
 
     multicore::resume_core1();
@@ -65,7 +87,7 @@ pub unsafe fn poll_bootsel_unsafe() -> bool {
 /// so we put it in the .data section and make sure it isn't inlined
 #[inline(never)]
 #[link_section = ".data.ram_func"]
-unsafe fn poll_bootsel_ramfunc(_: &critical_section::CriticalSection<'_>) -> bool {
+unsafe fn poll_bootsel_ramfunc(_: &critical_section::CriticalSection<'_>, settle_cycles: u32) -> bool {
     // Make sure the XIP controller is idle
     loop {
         let xip_status = rp_pac::XIP_CTRL.stat().read();
@@ -81,7 +103,7 @@ unsafe fn poll_bootsel_ramfunc(_: &critical_section::CriticalSection<'_>) -> boo
 
     // ...then wait for the state to settle...
     // (sleep might be in flash, use a delay loop)
-    cortex_m::asm::delay(2000);
+    cortex_m::asm::delay(settle_cycles);
 
     // ...we can read the current state of the button (active low)
     let button_state = !chip_select.status().read().infrompad();
@@ -91,3 +113,88 @@ unsafe fn poll_bootsel_ramfunc(_: &critical_section::CriticalSection<'_>) -> boo
 
     button_state
 }
+
+/// Resets the chip and re-enters the ROM's USB bootloader (mass storage + PICOBOOT),
+/// without requiring a hardware RUN reset or unplug.
+///
+/// `gpio_activity_pin_mask` and `disable_interface_mask` are passed straight through to
+/// the bootrom's `reset_usb_boot`; most applications can pass `0` for both. See the
+/// RP2040 datasheet's bootrom section for their meaning.
+///
+/// This function does not return: the chip resets before it would.
+pub fn reset_to_usb_boot(gpio_activity_pin_mask: u32, disable_interface_mask: u32) -> ! {
+    unsafe {
+        let reset_usb_boot = rom_data::reset_usb_boot();
+        reset_usb_boot(gpio_activity_pin_mask, disable_interface_mask);
+    }
+
+    // Unreachable: `reset_usb_boot` resets the chip before returning.
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+/// An async, debounced BOOTSEL input, for use as an ordinary button in an embassy
+/// application (e.g. a menu button).
+///
+/// Unlike [`poll_bootsel_unsafe`], reading a `BootSel` doesn't stall both cores for a
+/// whole debounce window: only the actual read - a brief, sub-10 µs flash-quiescing
+/// critical section - blocks, and the time spent waiting between samples is an
+/// `await`ed [`Timer`], so other tasks keep running.
+pub struct BootSel {
+    poll_interval: Duration,
+    settle_us: u32,
+}
+
+impl BootSel {
+    /// Creates a new `BootSel`, sampled every `poll_interval`.
+    pub fn new(poll_interval: Duration) -> Self {
+        Self::with_settle(poll_interval, DEFAULT_CS_SETTLE_US)
+    }
+
+    /// Like [`BootSel::new`], but overrides the CS settle time passed to
+    /// [`poll_bootsel_with_settle`].
+    pub fn with_settle(poll_interval: Duration, settle_us: u32) -> Self {
+        Self { poll_interval, settle_us }
+    }
+
+    /// Returns the button's current state, debounced over two samples `poll_interval`
+    /// apart (disagreeing samples are retried until they settle).
+    pub async fn read(&mut self) -> bool {
+        loop {
+            let first = unsafe { poll_bootsel_with_settle(self.settle_us) };
+            Timer::after(self.poll_interval).await;
+            let second = unsafe { poll_bootsel_with_settle(self.settle_us) };
+            if first == second {
+                return first;
+            }
+        }
+    }
+
+    /// Waits until the button reads as pressed.
+    pub async fn wait_for_press(&mut self) {
+        while !self.read().await {}
+    }
+}
+
+/// Waits for BOOTSEL to be held down continuously for `hold_duration`, then resets
+/// into the USB bootloader. Samples are debounced `poll_interval` apart via
+/// [`BootSel`].
+///
+/// Intended to be spawned as (or awaited from) an embassy task, to let firmware
+/// repurpose the BOOTSEL button as a runtime "re-enter the bootloader" trigger instead
+/// of requiring an unplug.
+pub async fn watch_for_bootsel_hold(hold_duration: Duration, poll_interval: Duration) -> ! {
+    let mut bootsel = BootSel::new(poll_interval);
+
+    loop {
+        bootsel.wait_for_press().await;
+
+        let held_since = Instant::now();
+        while bootsel.read().await {
+            if Instant::now() - held_since >= hold_duration {
+                reset_to_usb_boot(0, 0);
+            }
+        }
+    }
+}
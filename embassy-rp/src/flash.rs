@@ -0,0 +1,318 @@
+//! Internal QSPI flash read/erase/program
+//!
+//! The bulk of the RP2040's QSPI flash is occupied by the running program, but
+//! boards typically ship flash chips larger than the image, leaving an unused tail
+//! that applications can use to persist data (settings, calibration, logs, ...)
+//! across reboots.
+//!
+//! Erasing and programming flash both require taking the XIP controller out of
+//! execute-in-place mode, which means the CPU can't fetch instructions or data from
+//! flash for the duration. [`bootsel`](crate::bootsel) already solves the hard part
+//! of doing this safely (pausing core 1, draining flash DMA, waiting for the XIP
+//! controller to go idle, running from `.data.ram_func`); this module reuses the
+//! same approach to run the bootrom's erase/program routines.
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash as BlockingNorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash as BlockingReadNorFlash};
+use embedded_storage_async::nor_flash::{
+    MultiwriteNorFlash as AsyncMultiwriteNorFlash, NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash,
+};
+use rp_pac;
+
+use crate::multicore;
+use crate::rom_data;
+
+/// Offset of the XIP-mapped flash region in the RP2040's address space.
+pub const ADDR_OFFSET: u32 = 0x1000_0000;
+
+/// Alignment required for [`Flash::erase`]'s `offset` and `len`.
+pub const ERASE_SIZE: u32 = 4096;
+/// Alignment required for [`Flash::program`]'s `offset` and `data.len()`.
+pub const WRITE_SIZE: u32 = 256;
+/// Block size passed to the bootrom's block-erase command, used opportunistically
+/// when an erase range is large enough to benefit from it.
+const BLOCK_ERASE_SIZE: u32 = 65536;
+/// Standard SPI NOR 64 KiB block erase opcode.
+const BLOCK_ERASE_CMD: u8 = 0xd8;
+
+extern "C" {
+    static __flash_binary_start: u32;
+    static __flash_binary_end: u32;
+}
+
+/// Gives access to the internal QSPI flash's unused tail, for reading, erasing, and
+/// programming.
+///
+/// Owning a `Flash` means owning the ability to quiesce flash for erase/program; only
+/// one should exist at a time, and it must not be used from core 1.
+pub struct Flash {
+    _private: (),
+}
+
+impl Flash {
+    /// Creates a new `Flash` handle.
+    ///
+    /// # Safety
+    ///
+    /// Only one `Flash` (or other code that pauses core 1 and drives flash out of
+    /// XIP, such as [`bootsel::poll_bootsel_unsafe`](crate::bootsel::poll_bootsel_unsafe))
+    /// may be in use at a time, and core 1 must not be running code that accesses
+    /// flash for the duration of any call into it.
+    pub unsafe fn new() -> Self {
+        Self { _private: () }
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`, relative to the start of flash.
+    ///
+    /// Flash is memory-mapped, so unlike [`erase`](Self::erase) and
+    /// [`program`](Self::program) this never leaves XIP - it's a plain read.
+    pub fn read(&mut self, offset: u32, buf: &mut [u8]) {
+        let ptr = (ADDR_OFFSET + offset) as *const u8;
+        unsafe { core::ptr::copy_nonoverlapping(ptr, buf.as_mut_ptr(), buf.len()) };
+    }
+
+    /// Erases `len` bytes of flash starting at `offset`, relative to the start of
+    /// flash. Both must be a multiple of [`ERASE_SIZE`] (4 KiB); the bootrom
+    /// opportunistically uses 64 KiB block erases where the range allows it.
+    pub fn erase(&mut self, offset: u32, len: u32) {
+        assert_eq!(offset % ERASE_SIZE, 0, "erase offset must be a multiple of ERASE_SIZE");
+        assert_eq!(len % ERASE_SIZE, 0, "erase len must be a multiple of ERASE_SIZE");
+        check_no_overlap(offset, len);
+
+        self.with_flash_idle(|| unsafe { erase_ramfunc(offset, len) });
+    }
+
+    /// Programs `data` to flash starting at `offset`, relative to the start of flash.
+    /// Both `offset` and `data.len()` must be a multiple of [`WRITE_SIZE`] (256 bytes).
+    ///
+    /// The target range must already be erased.
+    pub fn program(&mut self, offset: u32, data: &[u8]) {
+        let len = data.len() as u32;
+        assert_eq!(offset % WRITE_SIZE, 0, "program offset must be a multiple of WRITE_SIZE");
+        assert_eq!(len % WRITE_SIZE, 0, "program len must be a multiple of WRITE_SIZE");
+        check_no_overlap(offset, len);
+
+        self.with_flash_idle(|| unsafe { program_ramfunc(offset, data.as_ptr(), len) });
+    }
+
+    /// Pauses core 1, waits for flash DMA and XIP streaming reads to go idle (the
+    /// same dance as [`bootsel::poll_bootsel_unsafe`](crate::bootsel::poll_bootsel_unsafe)),
+    /// then runs `f` with flash paused.
+    ///
+    /// `f` must do its flash work by calling one of this module's own `.data.ram_func`
+    /// entry points (e.g. [`erase_ramfunc`]), not inline in the closure: whether a generic closure's body
+    /// actually gets inlined into a RAM function is up to LLVM, not guaranteed by the
+    /// language, so each flash operation gets its own concrete RAM function instead.
+    fn with_flash_idle(&mut self, f: impl FnOnce()) {
+        assert!(rp_pac::SIO.cpuid().read() == 0, "Need to be on core 0");
+
+        multicore::pause_core1();
+
+        critical_section::with(|_cs| {
+            // Wait for all DMA channels accessing flash to finish.
+            const SRAM_LOWER: u32 = 0x2000_0000;
+            for n in 0..12 {
+                let ch = rp_pac::DMA.ch(n);
+                while ch.read_addr().read() < SRAM_LOWER && ch.ctrl_trig().read().busy() {}
+            }
+            // Wait for completion of any streaming reads.
+            while rp_pac::XIP_CTRL.stream_ctr().read().0 > 0 {}
+
+            f();
+        });
+
+        multicore::resume_core1();
+    }
+}
+
+/// Runs `flash_range_erase` from RAM with flash taken out of XIP.
+///
+/// # Safety
+///
+/// The caller must ensure flash is idle and will remain idle.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn erase_ramfunc(offset: u32, len: u32) {
+    let connect_internal_flash = rom_data::connect_internal_flash();
+    let flash_exit_xip = rom_data::flash_exit_xip();
+    let flash_range_erase = rom_data::flash_range_erase();
+    let flash_flush_cache = rom_data::flash_flush_cache();
+    let flash_enter_cmd_xip = rom_data::flash_enter_cmd_xip();
+
+    connect_internal_flash();
+    flash_exit_xip();
+
+    flash_range_erase(offset, len, BLOCK_ERASE_SIZE, BLOCK_ERASE_CMD);
+
+    flash_flush_cache();
+    flash_enter_cmd_xip();
+}
+
+/// Runs `flash_range_program` from RAM with flash taken out of XIP.
+///
+/// # Safety
+///
+/// The caller must ensure flash is idle and will remain idle, and that `data_ptr` is
+/// valid for `len` bytes.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn program_ramfunc(offset: u32, data_ptr: *const u8, len: u32) {
+    let connect_internal_flash = rom_data::connect_internal_flash();
+    let flash_exit_xip = rom_data::flash_exit_xip();
+    let flash_range_program = rom_data::flash_range_program();
+    let flash_flush_cache = rom_data::flash_flush_cache();
+    let flash_enter_cmd_xip = rom_data::flash_enter_cmd_xip();
+
+    connect_internal_flash();
+    flash_exit_xip();
+
+    flash_range_program(offset, data_ptr, len);
+
+    flash_flush_cache();
+    flash_enter_cmd_xip();
+}
+
+/// Panics if `[offset, offset+len)` overlaps the flash range occupied by the running
+/// program image, as given by the linker script's `__flash_binary_start`/`_end`.
+fn check_no_overlap(offset: u32, len: u32) {
+    let image_start = unsafe { &__flash_binary_start as *const u32 as u32 } - ADDR_OFFSET;
+    let image_end = unsafe { &__flash_binary_end as *const u32 as u32 } - ADDR_OFFSET;
+    let range_end = offset.checked_add(len).expect("offset + len overflowed");
+
+    assert!(
+        range_end <= image_start || offset >= image_end,
+        "flash range [{:#x}, {:#x}) overlaps the running program image [{:#x}, {:#x})",
+        offset,
+        range_end,
+        image_start,
+        image_end
+    );
+}
+
+/// Error returned by [`FlashRegion`]'s `embedded-storage` trait implementations.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The access fell outside the region's `[0, LEN)` range.
+    OutOfBounds,
+    /// The offset/length wasn't aligned to [`ERASE_SIZE`] (for an erase) or
+    /// [`WRITE_SIZE`] (for a write).
+    NotAligned,
+}
+
+impl NorFlashError for Error {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            Error::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            Error::NotAligned => NorFlashErrorKind::NotAligned,
+        }
+    }
+}
+
+/// A `[START, START + LEN)` window of flash, bounds-checked against its own range,
+/// with `embedded-storage` trait implementations so key/value or circular-log storage
+/// crates (e.g. `sequential-storage`) can be dropped on top of it directly instead of
+/// every application reinventing alignment and bounds bookkeeping.
+pub struct FlashRegion<'f, const START: u32, const LEN: u32> {
+    flash: &'f mut Flash,
+}
+
+impl<'f, const START: u32, const LEN: u32> FlashRegion<'f, START, LEN> {
+    /// Creates the region `[START, START + LEN)` of `flash`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `START`/`LEN` aren't erase-aligned, or the region overlaps the
+    /// running program image (see [`Flash::erase`]).
+    pub fn new(flash: &'f mut Flash) -> Self {
+        assert_eq!(START % ERASE_SIZE, 0, "FlashRegion start must be a multiple of ERASE_SIZE");
+        assert_eq!(LEN % ERASE_SIZE, 0, "FlashRegion len must be a multiple of ERASE_SIZE");
+        check_no_overlap(START, LEN);
+        Self { flash }
+    }
+
+    fn check_range(&self, offset: u32, len: u32) -> Result<(), Error> {
+        let end = offset.checked_add(len).ok_or(Error::OutOfBounds)?;
+        if end > LEN {
+            Err(Error::OutOfBounds)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_aligned(offset: u32, len: u32, granularity: u32) -> Result<(), Error> {
+        if offset % granularity != 0 || len % granularity != 0 {
+            Err(Error::NotAligned)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'f, const START: u32, const LEN: u32> ErrorType for FlashRegion<'f, START, LEN> {
+    type Error = Error;
+}
+
+impl<'f, const START: u32, const LEN: u32> BlockingReadNorFlash for FlashRegion<'f, START, LEN> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        self.check_range(offset, bytes.len() as u32)?;
+        self.flash.read(START + offset, bytes);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        LEN as usize
+    }
+}
+
+impl<'f, const START: u32, const LEN: u32> BlockingNorFlash for FlashRegion<'f, START, LEN> {
+    const WRITE_SIZE: usize = crate::flash::WRITE_SIZE as usize;
+    const ERASE_SIZE: usize = crate::flash::ERASE_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        let len = to.checked_sub(from).ok_or(Error::OutOfBounds)?;
+        self.check_range(from, len)?;
+        Self::check_aligned(from, len, ERASE_SIZE)?;
+        self.flash.erase(START + from, len);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        let len = bytes.len() as u32;
+        self.check_range(offset, len)?;
+        Self::check_aligned(offset, len, WRITE_SIZE)?;
+        self.flash.program(START + offset, bytes);
+        Ok(())
+    }
+}
+
+impl<'f, const START: u32, const LEN: u32> AsyncReadNorFlash for FlashRegion<'f, START, LEN> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Error> {
+        BlockingReadNorFlash::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        LEN as usize
+    }
+}
+
+impl<'f, const START: u32, const LEN: u32> AsyncNorFlash for FlashRegion<'f, START, LEN> {
+    const WRITE_SIZE: usize = crate::flash::WRITE_SIZE as usize;
+    const ERASE_SIZE: usize = crate::flash::ERASE_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Error> {
+        BlockingNorFlash::erase(self, from, to)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Error> {
+        BlockingNorFlash::write(self, offset, bytes)
+    }
+}
+
+// NOR flash can only clear bits during a program, never set them, so repeated writes
+// to the same page without an intervening erase are safe as long as later writes only
+// clear further bits - exactly what `MultiwriteNorFlash` requires.
+impl<'f, const START: u32, const LEN: u32> AsyncMultiwriteNorFlash for FlashRegion<'f, START, LEN> {}
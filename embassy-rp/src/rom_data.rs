@@ -0,0 +1,93 @@
+//! Access to functions baked into the RP2040's mask ROM.
+//!
+//! The bootrom exposes a table of function pointers, each tagged with a two character
+//! code, reachable from a magic value at a fixed address. This lets code already
+//! running from flash call back into ROM helpers - most importantly the ones that
+//! know how to safely take the QSPI flash in and out of execute-in-place (XIP) mode -
+//! without linking against the Pico SDK.
+//!
+//! See [`crate::flash`] and [`crate::bootsel`] for the call sites.
+
+use core::mem::transmute;
+
+const fn rom_table_code(c1: u8, c2: u8) -> u32 {
+    c1 as u32 | (c2 as u32) << 8
+}
+
+/// Looks up a bootrom function by its two character code.
+///
+/// # Safety
+///
+/// Only valid on an RP2040. The returned pointer is only meaningful once transmuted
+/// to the function's true signature, which the caller must get right.
+unsafe fn rom_func_lookup(code: u32) -> *const () {
+    // The magic value 'M' 'u' 1 at 0x00000010 confirms we're really talking to an
+    // RP2040 bootrom, and not, say, chasing pointers into whatever a debugger left
+    // at address zero.
+    const MAGIC_ADDR: *const u32 = 0x0000_0010 as *const u32;
+    const MAGIC: u32 = (b'M' as u32) | (b'u' as u32) << 8 | 1 << 16;
+
+    let magic = MAGIC_ADDR.read_volatile();
+    assert_eq!(
+        magic & 0x00ff_ffff,
+        MAGIC,
+        "bootrom magic not found, is this really running on an RP2040?"
+    );
+
+    // Immediately after the magic sits a pointer to the function table (with a data
+    // table pointer in between, which we don't need), and after that a pointer to the
+    // table lookup routine itself.
+    let func_table = (0x0000_0014 as *const u16).read_volatile();
+    let rom_table_lookup_ptr = (0x0000_0018 as *const u16).read_volatile();
+    let rom_table_lookup: extern "C" fn(*const u16, u32) -> *const () = transmute(rom_table_lookup_ptr as usize);
+
+    rom_table_lookup(func_table as *const u16, code)
+}
+
+pub(crate) type ConnectInternalFlashFn = unsafe extern "C" fn();
+pub(crate) type FlashExitXipFn = unsafe extern "C" fn();
+pub(crate) type FlashRangeEraseFn = unsafe extern "C" fn(addr: u32, count: u32, block_size: u32, block_cmd: u8);
+pub(crate) type FlashRangeProgramFn = unsafe extern "C" fn(addr: u32, data: *const u8, count: u32);
+pub(crate) type FlashFlushCacheFn = unsafe extern "C" fn();
+pub(crate) type FlashEnterCmdXipFn = unsafe extern "C" fn();
+pub(crate) type ResetUsbBootFn = unsafe extern "C" fn(gpio_activity_pin_mask: u32, disable_interface_mask: u32);
+
+/// Resolves `connect_internal_flash`, which reconnects the SSI to the internal flash
+/// pads before the XIP controller can be taken out of execute-in-place mode.
+pub(crate) unsafe fn connect_internal_flash() -> ConnectInternalFlashFn {
+    transmute(rom_func_lookup(rom_table_code(b'I', b'F')))
+}
+
+/// Resolves `flash_exit_xip`, which takes the SSI out of execute-in-place mode so raw
+/// erase/program commands can be issued.
+pub(crate) unsafe fn flash_exit_xip() -> FlashExitXipFn {
+    transmute(rom_func_lookup(rom_table_code(b'E', b'X')))
+}
+
+/// Resolves `flash_range_erase`.
+pub(crate) unsafe fn flash_range_erase() -> FlashRangeEraseFn {
+    transmute(rom_func_lookup(rom_table_code(b'R', b'E')))
+}
+
+/// Resolves `flash_range_program`.
+pub(crate) unsafe fn flash_range_program() -> FlashRangeProgramFn {
+    transmute(rom_func_lookup(rom_table_code(b'R', b'P')))
+}
+
+/// Resolves `flash_flush_cache`, which must be called before re-entering XIP so stale
+/// cache lines aren't served after an erase/program.
+pub(crate) unsafe fn flash_flush_cache() -> FlashFlushCacheFn {
+    transmute(rom_func_lookup(rom_table_code(b'F', b'C')))
+}
+
+/// Resolves `flash_enter_cmd_xip`, which restores execute-in-place after a raw
+/// erase/program sequence.
+pub(crate) unsafe fn flash_enter_cmd_xip() -> FlashEnterCmdXipFn {
+    transmute(rom_func_lookup(rom_table_code(b'C', b'X')))
+}
+
+/// Resolves `reset_usb_boot`, which resets the chip and re-enters the ROM USB
+/// bootloader.
+pub(crate) unsafe fn reset_usb_boot() -> ResetUsbBootFn {
+    transmute(rom_func_lookup(rom_table_code(b'U', b'B')))
+}